@@ -1,7 +1,21 @@
-use std::{collections::HashMap, fmt, str::FromStr};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    str::FromStr,
+};
 
-use anyhow::Result;
+use anyhow::{Result, anyhow};
+use argon2::{Algorithm, Argon2, Params, Version};
+use metrics_exporter_prometheus::PrometheusBuilder;
+use std::net::SocketAddr;
+use tracing::Instrument;
+use tracing_subscriber::prelude::*;
+use chacha20poly1305::{
+    ChaCha20Poly1305, Key,
+    aead::{Aead, KeyInit},
+};
 use clap::Parser;
+use std::sync::Arc;
 use futures_lite::StreamExt;
 use iroh::{Endpoint, NodeAddr, NodeId, protocol::Router};
 use iroh_gossip::net::GossipSender;
@@ -10,6 +24,7 @@ use iroh_gossip::{
     proto::TopicId,
 };
 use serde::{Deserialize, Serialize};
+use sqlx::{Row, sqlite::SqlitePoolOptions, SqlitePool};
 use std::io::{self, Write};
 #[derive(Parser, Debug)]
 struct Args {
@@ -17,8 +32,18 @@ struct Args {
     name: Option<String>,
     #[clap(short, long, default_value = "0")]
     bind_port: u16,
+    /// Serve Prometheus metrics on `0.0.0.0:<port>/metrics` when set.
+    #[clap(long)]
+    metrics_port: Option<u16>,
+    /// Export `tracing` spans to this OTLP endpoint (e.g. `http://localhost:4317`).
+    #[clap(long)]
+    otlp_endpoint: Option<String>,
+    /// Path to the SQLite chat-history database. Defaults to `$MESHSPACE_DB`,
+    /// then `meshspace.db` in the current directory.
+    #[clap(long)]
+    db_path: Option<String>,
     #[clap(subcommand)]
-    command: Command,
+    command: Option<Command>,
 }
 use std::time::Duration;
 use tokio::time::sleep;
@@ -43,10 +68,20 @@ fn render_ui(f: &mut Frame, state: &ChatState) {
         .constraints([Constraint::Min(5), Constraint::Length(3)])
         .split(f.area());
 
+    // Split the upper area into the chat log and a roster pane on the right.
+    let top = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Min(20), Constraint::Length(24)])
+        .split(chunks[0]);
+
     let chat = state
         .messages
         .iter()
-        .map(|(u, m)| format!("{u}: {m}"))
+        .map(|(ts, u, m, _)| {
+            let secs = ts / 1000;
+            let (h, m_) = ((secs / 3600) % 24, (secs / 60) % 60);
+            format!("{h:02}:{m_:02} {u}: {m}")
+        })
         .collect::<Vec<_>>()
         .join("\n");
 
@@ -55,10 +90,25 @@ fn render_ui(f: &mut Frame, state: &ChatState) {
         .wrap(Wrap { trim: true })
         .scroll((state.scroll_offset, 0));
 
+    let roster = state
+        .users
+        .iter()
+        .map(|(id, name)| {
+            // A microphone marks members currently in the voice channel.
+            let mic = if state.in_call.contains(id) { "🎙 " } else { "" };
+            format!("{mic}{name} ({})", id.fmt_short())
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let roster_widget = Paragraph::new(Text::from(roster))
+        .block(Block::default().title("👥 Members").borders(Borders::ALL))
+        .wrap(Wrap { trim: true });
+
     let input_widget = Paragraph::new(state.input.as_str())
         .block(Block::default().title("✍️ Message").borders(Borders::ALL));
 
-    f.render_widget(chat_widget, chunks[0]);
+    f.render_widget(chat_widget, top[0]);
+    f.render_widget(roster_widget, top[1]);
     f.render_widget(input_widget, chunks[1]);
     if let Ok(cursor_x) = (chunks[1].x as usize + state.input.len() + 1).try_into() {
         f.set_cursor_position((cursor_x, chunks[1].y + 1));
@@ -67,10 +117,52 @@ fn render_ui(f: &mut Frame, state: &ChatState) {
 
 #[derive(Default)]
 struct ChatState {
-    messages: Vec<(String, String)>,
+    /// `(timestamp millis, display name, text, nonce)` for every rendered line.
+    /// The timestamp orders lines under gossip's out-of-order delivery and the
+    /// nonce both breaks ties and de-duplicates replayed history.
+    messages: Vec<(u64, String, String, [u8; 16])>,
     input: String,
     users: HashMap<NodeId, String>,
+    /// Millis of the last `AboutMe` heartbeat seen from each member, used to
+    /// prune peers that have gone silent.
+    last_seen: HashMap<NodeId, u64>,
     scroll_offset: u16,
+    /// Nonces we have already displayed, so overlapping history replies from
+    /// several peers don't insert the same line twice.
+    seen: HashSet<[u8; 16]>,
+    /// Members currently present in the topic's voice channel.
+    in_call: HashSet<NodeId>,
+}
+
+impl ChatState {
+    /// Push a line, ignoring it if its nonce was already displayed. Lines are
+    /// kept sorted by `(timestamp, nonce)` so live and replayed messages
+    /// interleave chronologically.
+    fn push_message(&mut self, ts: u64, user: String, text: String, nonce: [u8; 16]) {
+        if self.seen.insert(nonce) {
+            self.messages.push((ts, user, text, nonce));
+            self.messages.sort_by_key(|(ts, _, _, nonce)| (*ts, *nonce));
+        }
+    }
+
+    /// Resolve a `/msg` target given either a member's name (case-insensitive)
+    /// or the short node id shown in the roster.
+    fn resolve_target(&self, key: &str) -> Option<NodeId> {
+        self.users
+            .iter()
+            .find(|(id, name)| {
+                name.eq_ignore_ascii_case(key) || id.fmt_short() == key
+            })
+            .map(|(id, _)| *id)
+    }
+}
+
+/// Current Unix time in milliseconds, used to timestamp outgoing lines.
+fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
 }
 use tokio::time::interval;
 
@@ -79,6 +171,11 @@ async fn chat_ui(
     sender: GossipSender,
     node_id: NodeId,
     display_name: Option<String>,
+    topic: TopicId,
+    storage: Storage,
+    cipher: Cipher,
+    endpoint: Endpoint,
+    voice: voice::VoiceHandler,
 ) -> Result<()> {
     enable_raw_mode()?;
     print!("\x1B[2J\x1B[1;1H"); // Clear terminal
@@ -87,18 +184,40 @@ async fn chat_ui(
 
     let mut state = ChatState::default();
     let mut rebroadcast = interval(Duration::from_secs(5));
+
+    // Restore our own persisted history so a node that reboots (or whose peers
+    // don't hold the backlog) still sees what was said. Encrypted rooms aren't
+    // persisted, so there's nothing to load for them.
+    if !cipher.is_encrypted() {
+        for (from, text, ts, nonce) in storage.recent(topic, HISTORY_LIMIT).await? {
+            state.push_message(ts, from.fmt_short(), text, nonce);
+        }
+    }
+
     // Send a "WhoIsThere" message so others reply with their AboutMe
-    sender
-        .broadcast(
-            Message::new(MessageBody::WhoIsThere { from: node_id })
-                .to_vec()
-                .into(),
-        )
-        .await?;
+    broadcast(
+        &sender,
+        &cipher,
+        &Message::new(MessageBody::WhoIsThere { from: node_id }),
+    )
+    .await?;
+
+    // Ask existing members to replay what was said before we joined.
+    broadcast(
+        &sender,
+        &cipher,
+        &Message::new(MessageBody::HistoryRequest {
+            from: node_id,
+            topic,
+            limit: HISTORY_LIMIT,
+        }),
+    )
+    .await?;
 
     // Insert our own name into state immediately
     if let Some(name) = &display_name {
         state.users.insert(node_id, name.clone());
+        state.last_seen.insert(node_id, now_millis());
     }
 
     // Spawn a thread to read terminal input and send it through channel
@@ -122,14 +241,174 @@ async fn chat_ui(
                             KeyCode::Esc => break,
                             KeyCode::Enter => {
                                 let msg = state.input.trim().to_string();
-                                if !msg.is_empty() {
+                                if msg.starts_with('/') {
+                                    // Slash commands never hit the wire as plain chat.
+                                    let mut parts = msg.splitn(3, ' ');
+                                    let cmd = parts.next().unwrap_or_default();
+                                    match cmd {
+                                        "/msg" | "/w" => {
+                                            let target = parts.next().unwrap_or_default();
+                                            let text = parts.next().unwrap_or_default().to_string();
+                                            match state.resolve_target(target) {
+                                                Some(to) if !text.is_empty() => {
+                                                    let ts = now_millis();
+                                                    let dm = Message::new(MessageBody::Direct {
+                                                        from: node_id,
+                                                        to,
+                                                        text: text.clone(),
+                                                        ts,
+                                                    });
+                                                    broadcast(&sender, &cipher, &dm).await?;
+                                                    metrics::counter!("meshspace_messages_sent_total", "variant" => "direct").increment(1);
+                                                    let who = state
+                                                        .users
+                                                        .get(&to)
+                                                        .cloned()
+                                                        .unwrap_or_else(|| to.fmt_short());
+                                                    state.push_message(
+                                                        ts,
+                                                        format!("[DM → {who}]"),
+                                                        text,
+                                                        dm.nonce,
+                                                    );
+                                                }
+                                                Some(_) => {
+                                                    state.push_message(
+                                                        now_millis(),
+                                                        "System".into(),
+                                                        "usage: /msg <name|node-id> <text>".into(),
+                                                        rand::random(),
+                                                    );
+                                                }
+                                                None => {
+                                                    state.push_message(
+                                                        now_millis(),
+                                                        "System".into(),
+                                                        format!("no such member: {target}"),
+                                                        rand::random(),
+                                                    );
+                                                }
+                                            }
+                                        }
+                                        "/who" => {
+                                            let mut roster = state
+                                                .users
+                                                .iter()
+                                                .map(|(id, name)| {
+                                                    format!("{name} ({})", id.fmt_short())
+                                                })
+                                                .collect::<Vec<_>>();
+                                            roster.sort();
+                                            state.push_message(
+                                                now_millis(),
+                                                "System".into(),
+                                                format!("members: {}", roster.join(", ")),
+                                                rand::random(),
+                                            );
+                                        }
+                                        "/call" => {
+                                            let joining = !state.in_call.contains(&node_id);
+                                            let announce = Message::new(MessageBody::VoiceState {
+                                                from: node_id,
+                                                joined: joining,
+                                            });
+                                            broadcast(&sender, &cipher, &announce).await?;
+                                            if joining {
+                                                state.in_call.insert(node_id);
+                                                // Start capturing once, then dial every known peer.
+                                                voice.join();
+                                                let peers: Vec<NodeId> = state
+                                                    .users
+                                                    .keys()
+                                                    .copied()
+                                                    .filter(|id| *id != node_id)
+                                                    .collect();
+                                                for peer in peers {
+                                                    voice.place_call(endpoint.clone(), peer);
+                                                }
+                                                state.push_message(
+                                                    now_millis(),
+                                                    "System".into(),
+                                                    "joined the voice channel".into(),
+                                                    rand::random(),
+                                                );
+                                            } else {
+                                                state.in_call.remove(&node_id);
+                                                voice.hang_up();
+                                                state.push_message(
+                                                    now_millis(),
+                                                    "System".into(),
+                                                    "left the voice channel".into(),
+                                                    rand::random(),
+                                                );
+                                            }
+                                        }
+                                        "/whois" => {
+                                            let target = parts.next().unwrap_or_default();
+                                            match state.resolve_target(target) {
+                                                Some(id) => {
+                                                    let name = state
+                                                        .users
+                                                        .get(&id)
+                                                        .cloned()
+                                                        .unwrap_or_default();
+                                                    let seen = state
+                                                        .last_seen
+                                                        .get(&id)
+                                                        .map(|ts| {
+                                                            let secs = ts / 1000;
+                                                            format!(
+                                                                ", last seen {:02}:{:02}:{:02}",
+                                                                (secs / 3600) % 24,
+                                                                (secs / 60) % 60,
+                                                                secs % 60
+                                                            )
+                                                        })
+                                                        .unwrap_or_default();
+                                                    state.push_message(
+                                                        now_millis(),
+                                                        "System".into(),
+                                                        format!("{name}: {id}{seen}"),
+                                                        rand::random(),
+                                                    );
+                                                }
+                                                None => {
+                                                    state.push_message(
+                                                        now_millis(),
+                                                        "System".into(),
+                                                        format!("no such member: {target}"),
+                                                        rand::random(),
+                                                    );
+                                                }
+                                            }
+                                        }
+                                        other => {
+                                            state.push_message(
+                                                now_millis(),
+                                                "System".into(),
+                                                format!("unknown command: {other}"),
+                                                rand::random(),
+                                            );
+                                        }
+                                    }
+                                    state.input.clear();
+                                } else if !msg.is_empty() {
+                                    let ts = now_millis();
                                     let outgoing = Message::new(MessageBody::Message {
                                         from: node_id,
                                         text: msg.clone(),
+                                        ts,
                                     });
-                                    sender.broadcast(outgoing.to_vec().into()).await?;
+                                    // Encrypted rooms are not persisted — see Storage docs.
+                                    if !cipher.is_encrypted() {
+                                        storage
+                                            .append(topic, outgoing.nonce, node_id, &msg, ts)
+                                            .await?;
+                                    }
+                                    broadcast(&sender, &cipher, &outgoing).await?;
+                                    metrics::counter!("meshspace_messages_sent_total", "variant" => "message").increment(1);
                                     let label = display_name.clone().unwrap_or_else(|| "You".into());
-                                    state.messages.push((label, msg));
+                                    state.push_message(ts, label, msg, outgoing.nonce);
                                     state.input.clear();
                                 }
                             }
@@ -163,7 +442,15 @@ async fn chat_ui(
                     }
 
                   Ok(Some(Event::Gossip(GossipEvent::Received(msg)))) = receiver.try_next() => {
-            if let Ok(msg) = Message::from_bytes(&msg.content) {
+            let decoded = tracing::info_span!("decode").in_scope(|| cipher.decode(&msg.content));
+            let Ok(msg) = decoded else {
+                // Undecodable or failed-auth frame: count it and drop silently.
+                metrics::counter!("meshspace_frames_dropped_total").increment(1);
+                continue;
+            };
+            {
+                let nonce = msg.nonce;
+                metrics::counter!("meshspace_messages_received_total", "variant" => variant_name(&msg.body)).increment(1);
                 match msg.body {
                     MessageBody::WhoIsThere { from } => {
                         // Ignore our own WhoIsThere
@@ -173,28 +460,100 @@ async fn chat_ui(
                                     from: node_id,
                                     name: name.clone(),
                                 });
-                                sender.broadcast(response.to_vec().into()).await?;
+                                broadcast(&sender, &cipher, &response).await?;
                             }
                         }
                     }
                     MessageBody::AboutMe { from, name } => {
                         if !state.users.contains_key(&from) {
-                            state.messages.push(("System".into(), format!("{name} joined")));
+                            state.push_message(
+                                now_millis(),
+                                "System".into(),
+                                format!("{name} joined"),
+                                nonce,
+                            );
                         }
                         state.users.insert(from, name.clone());
+                        state.last_seen.insert(from, now_millis());
                     }
 
-                    MessageBody::Message { from, text } => {
+                    MessageBody::Message { from, text, ts } => {
                         let name = state
                             .users
                             .get(&from)
                             .cloned()
                             .unwrap_or_else(|| from.fmt_short());
 
-                        state.messages.push((name, text));
+                        if !cipher.is_encrypted() {
+                            storage.append(topic, nonce, from, &text, ts).await?;
+                        }
+                        state.push_message(ts, name, text, nonce);
+                    }
 
+                    MessageBody::Direct { from, to, text, ts } => {
+                        // Only the addressed node renders a private message.
+                        if to == node_id {
+                            let name = state
+                                .users
+                                .get(&from)
+                                .cloned()
+                                .unwrap_or_else(|| from.fmt_short());
+                            state.push_message(ts, format!("[DM ← {name}]"), text, nonce);
+                        }
+                    }
+
+                    MessageBody::HistoryRequest {
+                        from,
+                        topic: wanted,
+                        limit,
+                    } => {
+                        // Reply to a fresh joiner with our most recent rows, split
+                        // into small batches so no single frame exceeds gossip's
+                        // max message size. A failed reply must not tear down our
+                        // own session, so broadcast errors are logged and ignored.
+                        if from != node_id && wanted == topic && !cipher.is_encrypted() {
+                            let rows = storage.recent(topic, limit).await?;
+                            for chunk in rows.chunks(HISTORY_CHUNK) {
+                                let reply = Message::new(MessageBody::HistoryBatch {
+                                    to: from,
+                                    messages: chunk.to_vec(),
+                                });
+                                if let Err(err) = broadcast(&sender, &cipher, &reply).await {
+                                    tracing::warn!(%err, "failed to send history batch");
+                                    break;
+                                }
+                            }
+                        }
+                    }
+
+                    MessageBody::HistoryBatch { to, messages } => {
+                        // Only the addressed joiner merges the replay.
+                        if to == node_id {
+                            for (from, text, ts, nonce) in messages {
+                                let name = state
+                                    .users
+                                    .get(&from)
+                                    .cloned()
+                                    .unwrap_or_else(|| from.fmt_short());
+                                state.push_message(ts, name, text, nonce);
+                            }
+                        }
+                    }
+
+                    MessageBody::VoiceState { from, joined } => {
+                        if joined {
+                            state.in_call.insert(from);
+                            // If we're already in the call, dial the newcomer so
+                            // audio flows both ways (they dial us in parallel).
+                            if from != node_id && state.in_call.contains(&node_id) {
+                                voice.place_call(endpoint.clone(), from);
+                            }
+                        } else {
+                            state.in_call.remove(&from);
+                        }
                     }
                 }
+                metrics::gauge!("meshspace_known_members").set(state.users.len() as f64);
             }
         }
                     _ = rebroadcast.tick() => {
@@ -203,8 +562,31 @@ async fn chat_ui(
                                 from: node_id,
                                 name: name.clone(),
                             });
-                            sender.broadcast(about.to_vec().into()).await?;
+                            broadcast(&sender, &cipher, &about).await?;
+                            state.last_seen.insert(node_id, now_millis());
                         }
+
+                        // Drop members we haven't heard an AboutMe heartbeat from
+                        // for a few rebroadcast intervals.
+                        let now = now_millis();
+                        let stale: Vec<NodeId> = state
+                            .last_seen
+                            .iter()
+                            .filter(|(id, &seen)| **id != node_id && now.saturating_sub(seen) > STALE_AFTER_MS)
+                            .map(|(id, _)| *id)
+                            .collect();
+                        for id in stale {
+                            let name = state
+                                .users
+                                .remove(&id)
+                                .unwrap_or_else(|| id.fmt_short());
+                            state.last_seen.remove(&id);
+                            state.push_message(now, "System".into(), format!("{name} left"), rand::random());
+                        }
+
+                        // Refresh the gauge every tick so it reflects pruning and
+                        // stays accurate in an otherwise quiet room.
+                        metrics::gauge!("meshspace_known_members").set(state.users.len() as f64);
                     }
 
                     _ = sleep(Duration::from_millis(100)) => {}
@@ -251,8 +633,66 @@ fn cli_header() {
     println!("----------------------------------------------------\n");
 }
 
+/// Broadcast an encoded message inside a `broadcast` span tagged with its
+/// variant, so traces show every frame we put on the wire.
+async fn broadcast(sender: &GossipSender, cipher: &Cipher, msg: &Message) -> Result<()> {
+    sender
+        .broadcast(cipher.encode(msg).into())
+        .instrument(tracing::info_span!("broadcast", variant = variant_name(&msg.body)))
+        .await?;
+    Ok(())
+}
+
+/// Short, stable label for a received [`MessageBody`] variant, used as the
+/// `variant` tag on the received-messages counter.
+fn variant_name(body: &MessageBody) -> &'static str {
+    match body {
+        MessageBody::WhoIsThere { .. } => "who_is_there",
+        MessageBody::AboutMe { .. } => "about_me",
+        MessageBody::Message { .. } => "message",
+        MessageBody::Direct { .. } => "direct",
+        MessageBody::HistoryRequest { .. } => "history_request",
+        MessageBody::HistoryBatch { .. } => "history_batch",
+        MessageBody::VoiceState { .. } => "voice_state",
+    }
+}
+
+/// Install the optional observability layers requested on the command line: a
+/// Prometheus scrape endpoint and/or an OTLP span exporter. Both are no-ops
+/// when their flags are absent.
+fn init_observability(args: &Args) -> Result<()> {
+    if let Some(port) = args.metrics_port {
+        let addr = SocketAddr::from(([0, 0, 0, 0], port));
+        PrometheusBuilder::new()
+            .with_http_listener(addr)
+            .install()
+            .map_err(|e| anyhow!("failed to install prometheus exporter: {e}"))?;
+    }
+
+    let registry = tracing_subscriber::registry().with(tracing_subscriber::EnvFilter::from_default_env());
+    match &args.otlp_endpoint {
+        Some(endpoint) => {
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(endpoint),
+                )
+                .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+            registry
+                .with(tracing_opentelemetry::layer().with_tracer(tracer))
+                .init();
+        }
+        None => registry.init(),
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    let args = Args::parse();
+    init_observability(&args)?;
     let mut input = String::new();
     cli_header();
     io::stdout().flush()?;
@@ -274,51 +714,96 @@ async fn main() -> Result<()> {
     print!("{}", bind_port);
     input.clear();
 
-    println!("\n");
-    println!("\x1B[36mChoose an option:\x1B[0m"); // "Choose an option:" in cyan
-    println!("1) Open a new chat room");
-    println!("2) Join an existing chat room");
+    // A subcommand on the command line skips the interactive menu.
+    let choice = match &args.command {
+        Some(Command::Open) => "1".to_string(),
+        Some(Command::Join { .. }) => "2".to_string(),
+        None => {
+            println!("\n");
+            println!("\x1B[36mChoose an option:\x1B[0m"); // "Choose an option:" in cyan
+            println!("1) Open a new chat room");
+            println!("2) Join an existing chat room");
 
-    io::stdout().flush()?;
-    io::stdin().read_line(&mut input)?;
-    let choice = input.trim().to_string();
-    input.clear();
+            io::stdout().flush()?;
+            io::stdin().read_line(&mut input)?;
+            let choice = input.trim().to_string();
+            input.clear();
+            choice
+        }
+    };
     cli_header();
 
-    let (topic, nodes) = if choice == "1" {
+    let (topic, nodes, encrypted, passphrase) = if choice == "1" {
         let topic = TopicId::from_bytes(rand::random());
         println!("\x1B[34m> opening chat room for topic \x1B[0m {}\n", topic);
-        (topic, vec![])
-    } else if choice == "2" {
-        print!("Enter ticket to join: ");
+        print!("Enter a room passphrase (blank for an open room): ");
         io::stdout().flush()?;
-        let mut ticket_input = String::new();
-        io::stdin().read_line(&mut ticket_input)?;
-        let ticket_str = ticket_input.trim();
+        io::stdin().read_line(&mut input)?;
+        let passphrase = input.trim().to_string();
+        input.clear();
+        (topic, vec![], !passphrase.is_empty(), passphrase)
+    } else if choice == "2" {
+        // Take the ticket from the `join` subcommand if given, else prompt.
+        let ticket_str = match &args.command {
+            Some(Command::Join { ticket }) => ticket.clone(),
+            _ => {
+                print!("Enter ticket to join: ");
+                io::stdout().flush()?;
+                let mut ticket_input = String::new();
+                io::stdin().read_line(&mut ticket_input)?;
+                ticket_input.trim().to_string()
+            }
+        };
 
-        let Ticket { topic, nodes } = Ticket::from_str(ticket_str)?;
+        let Ticket {
+            topic,
+            nodes,
+            encrypted,
+        } = Ticket::from_str(&ticket_str)?;
         println!(
             "\x1B[34m\n> joining chat room for topic \x1B[0m {}\n",
             topic
         );
-        (topic, nodes)
+        let passphrase = if encrypted {
+            print!("This room is encrypted. Enter the passphrase: ");
+            io::stdout().flush()?;
+            io::stdin().read_line(&mut input)?;
+            let passphrase = input.trim().to_string();
+            input.clear();
+            passphrase
+        } else {
+            String::new()
+        };
+        (topic, nodes, encrypted, passphrase)
     } else {
         println!("\x1B[31mInvalid choice\x1B[0m");
         return Ok(());
     };
+
+    let cipher = if encrypted {
+        Cipher::from_passphrase(&passphrase, topic)?
+    } else {
+        Cipher::Plain
+    };
     let endpoint = Endpoint::builder().discovery_n0().bind().await?;
     println!("\x1B[34m> our node id: \x1B[0m {}\n", endpoint.node_id());
 
     let gossip = Gossip::builder().spawn(endpoint.clone()).await?;
 
+    let voice = voice::VoiceHandler::new();
     let router = Router::builder(endpoint.clone())
         .accept(iroh_gossip::ALPN, gossip.clone())
+        .accept(voice::ALPN, voice.clone())
         .spawn();
 
     let ticket = {
         let me = endpoint.node_addr().await?;
         let nodes = vec![me];
-        Ticket { topic, nodes }
+        Ticket {
+            topic,
+            nodes,
+            encrypted,
+        }
     };
     println!("\x1B[34m> ticket to join us: \x1B[0m{}\n", ticket);
 
@@ -335,10 +820,32 @@ async fn main() -> Result<()> {
         }
     };
 
-    let (sender, receiver) = gossip.subscribe_and_join(topic, node_ids).await?.split();
+    let (sender, receiver) = gossip
+        .subscribe_and_join(topic, node_ids)
+        .instrument(tracing::info_span!("subscribe_and_join"))
+        .await?
+        .split();
     println!("\x1B[35m> connected!\x1B[0m");
 
-    chat_ui(receiver, sender, endpoint.node_id(), name).await?;
+    let db_path = args
+        .db_path
+        .clone()
+        .or_else(|| std::env::var("MESHSPACE_DB").ok())
+        .unwrap_or_else(|| "meshspace.db".to_string());
+    let storage = Storage::open(&format!("sqlite://{db_path}?mode=rwc")).await?;
+
+    chat_ui(
+        receiver,
+        sender,
+        endpoint.node_id(),
+        name,
+        topic,
+        storage,
+        cipher,
+        endpoint.clone(),
+        voice,
+    )
+    .await?;
     router.shutdown().await?;
 
     Ok(())
@@ -354,9 +861,44 @@ struct Message {
 enum MessageBody {
     WhoIsThere { from: NodeId },
     AboutMe { from: NodeId, name: String },
-    Message { from: NodeId, text: String },
+    Message { from: NodeId, text: String, ts: u64 },
+    /// One-to-one message: broadcast over gossip but only rendered by the node
+    /// whose `node_id == to` (and echoed locally to the sender).
+    Direct {
+        from: NodeId,
+        to: NodeId,
+        text: String,
+        ts: u64,
+    },
+    /// Broadcast by a freshly joined node asking members to replay history.
+    HistoryRequest {
+        from: NodeId,
+        topic: TopicId,
+        limit: u32,
+    },
+    /// Reply carrying a peer's most recent rows as `(sender, text, ts, nonce)`.
+    HistoryBatch {
+        to: NodeId,
+        messages: Vec<(NodeId, String, u64, [u8; 16])>,
+    },
+    /// Announces that `from` has joined (or left) the topic's voice channel so
+    /// the roster can show who is currently in the call.
+    VoiceState { from: NodeId, joined: bool },
 }
 
+/// How many rows a node asks for in its [`MessageBody::HistoryRequest`] and
+/// restores from its own store at startup. Members reply with the `limit`
+/// carried in the request.
+const HISTORY_LIMIT: u32 = 200;
+
+/// Rows per `HistoryBatch` reply, keeping each frame well under gossip's max
+/// message size rather than packing all [`HISTORY_LIMIT`] rows into one.
+const HISTORY_CHUNK: usize = 32;
+
+/// Members with no `AboutMe` heartbeat for this long (three rebroadcast
+/// intervals) are considered gone and pruned from the roster.
+const STALE_AFTER_MS: u64 = 15_000;
+
 impl Message {
     fn from_bytes(bytes: &[u8]) -> Result<Self> {
         serde_json::from_slice(bytes).map_err(Into::into)
@@ -374,10 +916,177 @@ impl Message {
     }
 }
 
+/// Encrypted wire frame: the plaintext [`Message::body`] is AEAD-sealed and
+/// only the ciphertext plus the nonce travel over gossip.
+#[derive(Debug, Serialize, Deserialize)]
+struct Frame {
+    nonce: [u8; 16],
+    ciphertext: Vec<u8>,
+}
+
+/// Optional per-room encryption. Plaintext rooms serialize bodies as JSON;
+/// encrypted rooms seal them with ChaCha20-Poly1305 keyed from the passphrase.
+#[derive(Clone)]
+enum Cipher {
+    Plain,
+    Encrypted(Arc<ChaCha20Poly1305>),
+}
+
+impl Cipher {
+    /// Derive a room cipher from `passphrase`, using the topic as the Argon2id
+    /// salt so every peer converges on the same 32-byte key.
+    fn from_passphrase(passphrase: &str, topic: TopicId) -> Result<Self> {
+        let salt = &topic.as_bytes()[..16];
+        let argon = Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::default());
+        let mut key = [0u8; 32];
+        argon
+            .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+            .map_err(|e| anyhow!("key derivation failed: {e}"))?;
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+        Ok(Cipher::Encrypted(Arc::new(cipher)))
+    }
+
+    /// Whether this room is encrypted. Persistence is disabled for encrypted
+    /// rooms so confidential traffic is never written to disk as plaintext.
+    fn is_encrypted(&self) -> bool {
+        matches!(self, Cipher::Encrypted(_))
+    }
+
+    /// Encode a message for the wire, sealing it when encryption is enabled.
+    fn encode(&self, msg: &Message) -> Vec<u8> {
+        match self {
+            Cipher::Plain => msg.to_vec(),
+            Cipher::Encrypted(cipher) => {
+                let body =
+                    serde_json::to_vec(&msg.body).expect("serde_json::to_vec is infallible");
+                let ciphertext = cipher
+                    .encrypt(aead_nonce(&msg.nonce), body.as_ref())
+                    .expect("AEAD encryption is infallible");
+                serde_json::to_vec(&Frame {
+                    nonce: msg.nonce,
+                    ciphertext,
+                })
+                .expect("serde_json::to_vec is infallible")
+            }
+        }
+    }
+
+    /// Decode a wire frame, returning `Err` on auth failure so callers drop it.
+    fn decode(&self, bytes: &[u8]) -> Result<Message> {
+        match self {
+            Cipher::Plain => Message::from_bytes(bytes),
+            Cipher::Encrypted(cipher) => {
+                let frame: Frame = serde_json::from_slice(bytes)?;
+                let plaintext = cipher
+                    .decrypt(aead_nonce(&frame.nonce), frame.ciphertext.as_ref())
+                    .map_err(|_| anyhow!("AEAD authentication failed"))?;
+                let body = serde_json::from_slice(&plaintext)?;
+                Ok(Message {
+                    body,
+                    nonce: frame.nonce,
+                })
+            }
+        }
+    }
+}
+
+/// The 12-byte AEAD nonce is the first 12 bytes of the 16-byte message nonce.
+fn aead_nonce(nonce: &[u8; 16]) -> &chacha20poly1305::Nonce {
+    chacha20poly1305::Nonce::from_slice(&nonce[..12])
+}
+
+/// SQLite-backed chat log. Every [`MessageBody::Message`] is appended here so a
+/// node keeps its history across reboots and can replay it to late joiners.
+///
+/// Persistence is only used for plaintext rooms: the `text` column stores
+/// cleartext, so callers disable it for encrypted rooms (see
+/// [`Cipher::is_encrypted`]) to keep confidential traffic off disk. Encrypted
+/// rooms are therefore ephemeral.
+#[derive(Clone)]
+struct Storage {
+    pool: SqlitePool,
+}
+
+impl Storage {
+    /// Open (creating if needed) the database at `url` and ensure the schema.
+    async fn open(url: &str) -> Result<Self> {
+        let pool = SqlitePoolOptions::new().connect(url).await?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS messages (
+                id      INTEGER PRIMARY KEY AUTOINCREMENT,
+                topic   BLOB NOT NULL,
+                nonce   BLOB NOT NULL,
+                sender  TEXT NOT NULL,
+                text    TEXT NOT NULL,
+                ts      INTEGER NOT NULL,
+                UNIQUE(topic, nonce)
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        Ok(Self { pool })
+    }
+
+    /// Append one message, keyed by `(topic, nonce)`. Re-seen nonces are ignored.
+    async fn append(
+        &self,
+        topic: TopicId,
+        nonce: [u8; 16],
+        from: NodeId,
+        text: &str,
+        ts: u64,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT OR IGNORE INTO messages (topic, nonce, sender, text, ts)
+             VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(topic.as_bytes().as_slice())
+        .bind(nonce.as_slice())
+        .bind(from.to_string())
+        .bind(text)
+        .bind(ts as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// The most recent `limit` rows for `topic`, oldest first.
+    async fn recent(
+        &self,
+        topic: TopicId,
+        limit: u32,
+    ) -> Result<Vec<(NodeId, String, u64, [u8; 16])>> {
+        let rows = sqlx::query(
+            "SELECT sender, text, ts, nonce FROM messages
+             WHERE topic = ? ORDER BY id DESC LIMIT ?",
+        )
+        .bind(topic.as_bytes().as_slice())
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut out = Vec::with_capacity(rows.len());
+        for row in rows.into_iter().rev() {
+            let sender: String = row.get("sender");
+            let text: String = row.get("text");
+            let ts: i64 = row.get("ts");
+            let nonce: Vec<u8> = row.get("nonce");
+            let (Ok(from), Ok(nonce)) = (NodeId::from_str(&sender), nonce.try_into()) else {
+                continue;
+            };
+            out.push((from, text, ts as u64, nonce));
+        }
+        Ok(out)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Ticket {
     topic: TopicId,
     nodes: Vec<NodeAddr>,
+    /// Whether the room is end-to-end encrypted, so joiners know to prompt for
+    /// the passphrase before subscribing.
+    encrypted: bool,
 }
 
 impl Ticket {
@@ -405,3 +1114,506 @@ impl FromStr for Ticket {
         Self::from_bytes(&bytes)
     }
 }
+
+/// Opt-in peer-to-peer voice channel layered on the gossip `Endpoint`. Audio is
+/// captured as PCM, encoded with Opus, packetized as RTP and streamed to peers
+/// over dedicated iroh bidirectional streams on the `meshspace/voice` ALPN. The
+/// receive side reorders packets through a jitter buffer before playback.
+mod voice {
+    use super::*;
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+    use iroh::endpoint::Connection;
+    use iroh::protocol::{AcceptError, ProtocolHandler};
+    use opus::{Channels, Decoder, Encoder};
+    use std::collections::BTreeMap;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tokio::sync::broadcast;
+
+    /// ALPN for the voice protocol, accepted alongside gossip on the `Router`.
+    pub const ALPN: &[u8] = b"meshspace/voice";
+
+    /// 48 kHz mono, 20 ms frames — the Opus "voip" sweet spot.
+    const SAMPLE_RATE: u32 = 48_000;
+    const FRAME_SAMPLES: usize = 960;
+    /// Opus dynamic payload type used in the RTP header.
+    const PAYLOAD_TYPE: u8 = 111;
+    /// How many out-of-order packets the jitter buffer will hold before it gives
+    /// up waiting for a gap and skips ahead.
+    const JITTER_CAPACITY: usize = 16;
+
+    /// A single RTP packet carrying one Opus frame.
+    pub struct RtpPacket {
+        pub seq: u16,
+        pub timestamp: u32,
+        pub ssrc: u32,
+        pub payload: Vec<u8>,
+    }
+
+    impl RtpPacket {
+        /// Serialize to the 12-byte RTP header followed by the Opus payload.
+        pub fn encode(&self) -> Vec<u8> {
+            let mut buf = Vec::with_capacity(12 + self.payload.len());
+            buf.push(0x80); // version 2, no padding/extension/CSRC
+            buf.push(PAYLOAD_TYPE & 0x7f); // marker bit clear
+            buf.extend_from_slice(&self.seq.to_be_bytes());
+            buf.extend_from_slice(&self.timestamp.to_be_bytes());
+            buf.extend_from_slice(&self.ssrc.to_be_bytes());
+            buf.extend_from_slice(&self.payload);
+            buf
+        }
+
+        /// Parse a packet, returning `None` if the header is malformed.
+        pub fn decode(bytes: &[u8]) -> Option<Self> {
+            if bytes.len() < 12 {
+                return None;
+            }
+            Some(Self {
+                seq: u16::from_be_bytes([bytes[2], bytes[3]]),
+                timestamp: u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+                ssrc: u32::from_be_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+                payload: bytes[12..].to_vec(),
+            })
+        }
+    }
+
+    /// Reorders packets by RTP sequence number and drops ones that arrive after
+    /// their slot has already been played out.
+    #[derive(Default)]
+    pub struct JitterBuffer {
+        next: Option<u16>,
+        queue: BTreeMap<u16, Vec<u8>>,
+    }
+
+    impl JitterBuffer {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Insert a packet, discarding it if it is late. When the buffer is full,
+        /// playout first skips ahead to the oldest packet held.
+        pub fn push(&mut self, pkt: RtpPacket) {
+            if let Some(next) = self.next {
+                // `seq` wraps at u16::MAX; treat the recent past as "late".
+                if pkt.seq.wrapping_sub(next) > u16::MAX / 2 {
+                    return; // already played past this slot
+                }
+            }
+            if self.queue.len() >= JITTER_CAPACITY {
+                // Buffer overrun: skip ahead to the oldest packet we hold.
+                if let Some((&lowest, _)) = self.queue.iter().next() {
+                    self.next = Some(lowest);
+                }
+            }
+            self.queue.insert(pkt.seq, pkt.payload);
+        }
+
+        /// Pop the next in-order frame if it is available.
+        pub fn pop(&mut self) -> Option<Vec<u8>> {
+            let seq = match self.next {
+                Some(seq) => seq,
+                None => *self.queue.keys().next()?,
+            };
+            let frame = self.queue.remove(&seq)?;
+            self.next = Some(seq.wrapping_add(1));
+            Some(frame)
+        }
+    }
+
+    /// Shared voice state: the SSRC identifying our stream, whether a call is
+    /// currently active (cleared by `/call` a second time), and a broadcast
+    /// channel of encoded RTP packets that every peer connection subscribes to.
+    #[derive(Clone)]
+    pub struct VoiceHandler {
+        ssrc: u32,
+        active: Arc<AtomicBool>,
+        packets: broadcast::Sender<Vec<u8>>,
+    }
+
+    impl VoiceHandler {
+        pub fn new() -> Self {
+            let (packets, _) = broadcast::channel(64);
+            Self {
+                ssrc: rand::random(),
+                active: Arc::new(AtomicBool::new(false)),
+                packets,
+            }
+        }
+
+        /// Join the voice channel: capture and Opus-encode the microphone exactly
+        /// once, publishing RTP packets to the shared channel. A no-op if a call
+        /// is already active.
+        pub fn join(&self) {
+            // Only the transition false -> true starts the single capture task.
+            if self.active.swap(true, Ordering::SeqCst) {
+                return;
+            }
+            let active = self.active.clone();
+            let ssrc = self.ssrc;
+            let packets = self.packets.clone();
+            tokio::spawn(async move {
+                if let Err(err) = capture_loop(ssrc, packets, active).await {
+                    tracing::warn!(%err, "voice capture ended");
+                }
+            });
+        }
+
+        /// Dial `peer` on the voice ALPN and forward the captured RTP stream to
+        /// it until [`VoiceHandler::hang_up`] is called.
+        pub fn place_call(&self, endpoint: Endpoint, peer: NodeId) {
+            let active = self.active.clone();
+            let rx = self.packets.subscribe();
+            tokio::spawn(async move {
+                if let Err(err) = send_audio(endpoint, peer, rx, active).await {
+                    tracing::warn!(%peer, %err, "voice call ended");
+                }
+            });
+        }
+
+        /// Leave the voice channel, stopping capture.
+        pub fn hang_up(&self) {
+            self.active.store(false, Ordering::SeqCst);
+        }
+    }
+
+    impl Default for VoiceHandler {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl ProtocolHandler for VoiceHandler {
+        async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+            let (_send, mut recv) = connection.accept_bi().await?;
+            let mut jitter = JitterBuffer::new();
+            let mut decoder = Decoder::new(SAMPLE_RATE, Channels::Mono)
+                .map_err(AcceptError::from_err)?;
+            let player = Player::new().map_err(AcceptError::from_err)?;
+
+            // Each length-prefixed frame on the stream is one RTP packet.
+            while let Ok(Some(frame)) = read_frame(&mut recv).await {
+                if let Some(pkt) = RtpPacket::decode(&frame) {
+                    jitter.push(pkt);
+                }
+                while let Some(opus) = jitter.pop() {
+                    let mut pcm = vec![0i16; FRAME_SAMPLES];
+                    if decoder.decode(&opus, &mut pcm, false).is_ok() {
+                        player.play(&pcm);
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Capture microphone audio once, Opus-encode it and publish each RTP packet
+    /// to `packets` for every peer connection to forward.
+    async fn capture_loop(
+        ssrc: u32,
+        packets: broadcast::Sender<Vec<u8>>,
+        active: Arc<AtomicBool>,
+    ) -> Result<()> {
+        let mut encoder = Encoder::new(SAMPLE_RATE, Channels::Mono, opus::Application::Voip)?;
+        let mut pcm = Capture::new()?;
+
+        let mut seq: u16 = 0;
+        let mut timestamp: u32 = 0;
+        while active.load(Ordering::SeqCst) {
+            let frame = pcm.next_frame().await;
+            let opus = encoder.encode_vec(&frame, FRAME_SAMPLES)?;
+            let packet = RtpPacket {
+                seq,
+                timestamp,
+                ssrc,
+                payload: opus,
+            };
+            // No subscribers yet simply means no peers are connected.
+            let _ = packets.send(packet.encode());
+            seq = seq.wrapping_add(1);
+            timestamp = timestamp.wrapping_add(FRAME_SAMPLES as u32);
+        }
+        Ok(())
+    }
+
+    /// Forward the shared RTP stream to a single `peer` for as long as the call
+    /// is active.
+    async fn send_audio(
+        endpoint: Endpoint,
+        peer: NodeId,
+        mut packets: broadcast::Receiver<Vec<u8>>,
+        active: Arc<AtomicBool>,
+    ) -> Result<()> {
+        let connection = endpoint.connect(peer, ALPN).await?;
+        let (mut send, _recv) = connection.open_bi().await?;
+
+        while active.load(Ordering::SeqCst) {
+            match packets.recv().await {
+                Ok(frame) => write_frame(&mut send, &frame).await?,
+                // Fell behind the capture task: skip the dropped packets.
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+        send.finish()?;
+        Ok(())
+    }
+
+    /// Read one length-prefixed frame (`u16` big-endian length + body).
+    async fn read_frame(recv: &mut iroh::endpoint::RecvStream) -> Result<Option<Vec<u8>>> {
+        let mut len = [0u8; 2];
+        if recv.read_exact(&mut len).await.is_err() {
+            return Ok(None);
+        }
+        let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+        recv.read_exact(&mut buf).await?;
+        Ok(Some(buf))
+    }
+
+    /// Write one length-prefixed frame.
+    async fn write_frame(send: &mut iroh::endpoint::SendStream, body: &[u8]) -> Result<()> {
+        send.write_all(&(body.len() as u16).to_be_bytes()).await?;
+        send.write_all(body).await?;
+        Ok(())
+    }
+
+    /// Microphone capture that hands 20 ms PCM frames to the async encoder via a
+    /// channel fed from cpal's realtime callback thread.
+    struct Capture {
+        rx: tokio::sync::mpsc::Receiver<Vec<i16>>,
+        _stream: cpal::Stream,
+    }
+
+    /// Log stream errors from cpal's realtime thread. A free `fn` so it can be
+    /// reused across the per-format match arms (closures aren't `Copy`).
+    fn input_err(err: cpal::StreamError) {
+        tracing::warn!(%err, "audio input error");
+    }
+
+    impl Capture {
+        fn new() -> Result<Self> {
+            let device = cpal::default_host()
+                .default_input_device()
+                .ok_or_else(|| anyhow!("no input device"))?;
+            let supported = device.default_input_config()?;
+            let sample_format = supported.sample_format();
+            let config = supported.config();
+            let (tx, rx) = tokio::sync::mpsc::channel(32);
+
+            // The default input format is `f32` on many platforms, so convert to
+            // the `i16` samples Opus expects rather than demanding an `i16` stream.
+            let stream = match sample_format {
+                cpal::SampleFormat::I16 => {
+                    let mut acc: Vec<i16> = Vec::with_capacity(FRAME_SAMPLES);
+                    device.build_input_stream(
+                        &config,
+                        move |data: &[i16], _| {
+                            acc.extend_from_slice(data);
+                            while acc.len() >= FRAME_SAMPLES {
+                                let _ = tx.try_send(acc.drain(..FRAME_SAMPLES).collect());
+                            }
+                        },
+                        input_err,
+                        None,
+                    )?
+                }
+                cpal::SampleFormat::F32 => {
+                    let mut acc: Vec<i16> = Vec::with_capacity(FRAME_SAMPLES);
+                    device.build_input_stream(
+                        &config,
+                        move |data: &[f32], _| {
+                            acc.extend(data.iter().map(|s| (s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16));
+                            while acc.len() >= FRAME_SAMPLES {
+                                let _ = tx.try_send(acc.drain(..FRAME_SAMPLES).collect());
+                            }
+                        },
+                        input_err,
+                        None,
+                    )?
+                }
+                cpal::SampleFormat::U16 => {
+                    let mut acc: Vec<i16> = Vec::with_capacity(FRAME_SAMPLES);
+                    device.build_input_stream(
+                        &config,
+                        move |data: &[u16], _| {
+                            acc.extend(data.iter().map(|s| (*s as i32 - 32768) as i16));
+                            while acc.len() >= FRAME_SAMPLES {
+                                let _ = tx.try_send(acc.drain(..FRAME_SAMPLES).collect());
+                            }
+                        },
+                        input_err,
+                        None,
+                    )?
+                }
+                other => return Err(anyhow!("unsupported input sample format: {other:?}")),
+            };
+            stream.play()?;
+            Ok(Self { rx, _stream: stream })
+        }
+
+        async fn next_frame(&mut self) -> Vec<i16> {
+            self.rx.recv().await.unwrap_or_else(|| vec![0i16; FRAME_SAMPLES])
+        }
+    }
+
+    /// Audio playback that pushes decoded PCM frames to the default output device
+    /// through a channel drained by cpal's realtime callback thread.
+    struct Player {
+        tx: std::sync::mpsc::Sender<Vec<i16>>,
+        _stream: cpal::Stream,
+    }
+
+    impl Player {
+        fn new() -> Result<Self> {
+            let device = cpal::default_host()
+                .default_output_device()
+                .ok_or_else(|| anyhow!("no output device"))?;
+            let config = device.default_output_config()?.config();
+            let (tx, rx) = std::sync::mpsc::channel::<Vec<i16>>();
+            let mut pending: std::collections::VecDeque<i16> = std::collections::VecDeque::new();
+            let stream = device.build_output_stream(
+                &config,
+                move |out: &mut [i16], _| {
+                    while pending.len() < out.len() {
+                        match rx.try_recv() {
+                            Ok(frame) => pending.extend(frame),
+                            Err(_) => break,
+                        }
+                    }
+                    for sample in out.iter_mut() {
+                        *sample = pending.pop_front().unwrap_or(0);
+                    }
+                },
+                |err| tracing::warn!(%err, "audio output error"),
+                None,
+            )?;
+            stream.play()?;
+            Ok(Self { tx, _stream: stream })
+        }
+
+        fn play(&self, pcm: &[i16]) {
+            let _ = self.tx.send(pcm.to_vec());
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn rtp_packet_round_trips() {
+            let pkt = RtpPacket {
+                seq: 42,
+                timestamp: 960,
+                ssrc: 0xdead_beef,
+                payload: vec![1, 2, 3, 4, 5],
+            };
+            let decoded = RtpPacket::decode(&pkt.encode()).expect("decodes");
+            assert_eq!(decoded.seq, pkt.seq);
+            assert_eq!(decoded.timestamp, pkt.timestamp);
+            assert_eq!(decoded.ssrc, pkt.ssrc);
+            assert_eq!(decoded.payload, pkt.payload);
+        }
+
+        #[test]
+        fn rtp_decode_rejects_short_frames() {
+            assert!(RtpPacket::decode(&[0u8; 11]).is_none());
+        }
+
+        fn pkt(seq: u16) -> RtpPacket {
+            RtpPacket {
+                seq,
+                timestamp: 0,
+                ssrc: 0,
+                payload: vec![seq as u8],
+            }
+        }
+
+        #[test]
+        fn jitter_buffer_reorders_by_sequence() {
+            let mut jb = JitterBuffer::new();
+            jb.push(pkt(2));
+            jb.push(pkt(0));
+            jb.push(pkt(1));
+            assert_eq!(jb.pop(), Some(vec![0]));
+            assert_eq!(jb.pop(), Some(vec![1]));
+            assert_eq!(jb.pop(), Some(vec![2]));
+            assert_eq!(jb.pop(), None);
+        }
+
+        #[test]
+        fn jitter_buffer_drops_late_packets() {
+            let mut jb = JitterBuffer::new();
+            jb.push(pkt(0));
+            jb.push(pkt(1));
+            assert_eq!(jb.pop(), Some(vec![0])); // next is now 1
+            // Packet 0 arrives late; it must be discarded, not replayed.
+            jb.push(pkt(0));
+            assert_eq!(jb.pop(), Some(vec![1]));
+            assert_eq!(jb.pop(), None);
+        }
+
+        #[test]
+        fn jitter_buffer_handles_sequence_wrap() {
+            let mut jb = JitterBuffer::new();
+            jb.push(pkt(u16::MAX));
+            assert_eq!(jb.pop(), Some(vec![u16::MAX as u8]));
+            // The wrap to 0 is the in-order successor, not a late packet.
+            jb.push(pkt(0));
+            assert_eq!(jb.pop(), Some(vec![0]));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_topic() -> TopicId {
+        TopicId::from_bytes([7u8; 32])
+    }
+
+    fn sample_message() -> Message {
+        Message::new(MessageBody::Message {
+            from: NodeId::from_bytes(&[1u8; 32]).unwrap(),
+            text: "hello mesh".into(),
+            ts: 1_700_000_000_000,
+        })
+    }
+
+    #[test]
+    fn cipher_round_trips_encrypted_body() {
+        let cipher = Cipher::from_passphrase("correct horse", test_topic()).unwrap();
+        let msg = sample_message();
+        let decoded = cipher.decode(&cipher.encode(&msg)).expect("decrypts");
+
+        assert_eq!(decoded.nonce, msg.nonce);
+        match (decoded.body, msg.body) {
+            (
+                MessageBody::Message { text: a, ts: ta, .. },
+                MessageBody::Message { text: b, ts: tb, .. },
+            ) => {
+                assert_eq!(a, b);
+                assert_eq!(ta, tb);
+            }
+            _ => panic!("variant changed across round trip"),
+        }
+    }
+
+    #[test]
+    fn cipher_rejects_wrong_passphrase() {
+        let topic = test_topic();
+        let sealed = Cipher::from_passphrase("right", topic)
+            .unwrap()
+            .encode(&sample_message());
+        let wrong = Cipher::from_passphrase("wrong", topic).unwrap();
+
+        // Auth failure surfaces as an error so the receive loop drops the frame.
+        assert!(wrong.decode(&sealed).is_err());
+    }
+
+    #[test]
+    fn plain_cipher_is_transparent() {
+        let msg = sample_message();
+        let decoded = Cipher::Plain.decode(&Cipher::Plain.encode(&msg)).unwrap();
+        assert_eq!(decoded.nonce, msg.nonce);
+    }
+}